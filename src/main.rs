@@ -1,13 +1,19 @@
-use std::{ops::Sub, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    f32::consts::TAU,
+    ops::Sub,
+    time::Duration,
+};
 
 use bevy::{
     app::{App, FixedUpdate, PostUpdate, PreUpdate, Startup},
     asset::Assets,
     color::Color,
+    input::{keyboard::KeyCode, mouse::MouseButton, ButtonInput},
     math::Vec2,
     prelude::{
-        Bundle, Camera2dBundle, Circle, Commands, Component, Entity, In, IntoSystem, Mesh,
-        PluginGroup, Query, Res, ResMut, Resource, Transform, With, World,
+        Bundle, Camera, Camera2dBundle, Circle, Commands, Component, Entity, GlobalTransform, In,
+        IntoSystem, Mesh, PluginGroup, Query, Res, ResMut, Resource, Transform, With,
     },
     sprite::{ColorMaterial, MaterialMesh2dBundle, Mesh2dHandle},
     time::{Fixed, Time},
@@ -15,7 +21,9 @@ use bevy::{
     DefaultPlugins,
 };
 use bevy_spatial::{kdtree::KDTree2, AutomaticUpdate, SpatialAccess, SpatialStructure};
+use opensimplex_noise_rs::OpenSimplexNoise;
 use rand::{thread_rng, Rng};
+use rayon::prelude::*;
 
 const PARTICLE_SIZE: f32 = 1.5;
 const NUMBER_OF_PARTICLES: u64 = 10000;
@@ -42,13 +50,30 @@ fn main() {
         }))
         .insert_resource(Time::<Fixed>::from_hz(10.0))
         .init_resource::<AttractionFactors>()
+        .init_resource::<ForceModel>()
+        .init_resource::<BoidsFactors>()
+        .init_resource::<GravityFactors>()
+        .init_resource::<CursorInteraction>()
+        .init_resource::<CursorState>()
+        .init_resource::<CollisionFactors>()
         .add_systems(Startup, setup)
-        .add_systems(PreUpdate, position_to_translation)
+        .add_systems(
+            PreUpdate,
+            (
+                position_to_translation,
+                handle_mouse_interaction,
+                cycle_force_model,
+            ),
+        )
         .add_systems(
             FixedUpdate,
-            get_particles
-                .pipe(compute_acceleration)
-                .pipe(apply_acceleration),
+            (
+                get_particles
+                    .pipe(compute_acceleration)
+                    .pipe(apply_acceleration),
+                handle_collisions,
+            )
+                .chain(),
         )
         .add_systems(PostUpdate, wrap_particles)
         .run();
@@ -76,12 +101,24 @@ impl Sub<Position> for Position {
 #[derive(Component, Default, Clone, Copy)]
 struct Type(usize);
 
+#[derive(Component, Clone, Copy)]
+struct Mass(f32);
+
+#[derive(Component, Clone, Copy)]
+struct Radius(f32);
+
+fn mass_for_radius(radius: f32) -> f32 {
+    radius * radius
+}
+
 #[derive(Bundle)]
 struct Particle {
     point: Point,
     velocity: Velocity,
     position: Position,
     ty: Type,
+    mass: Mass,
+    radius: Radius,
     mesh: MaterialMesh2dBundle<ColorMaterial>,
 }
 
@@ -108,6 +145,301 @@ impl Default for AttractionFactors {
     }
 }
 
+#[derive(Resource, Clone, Copy, PartialEq, Eq, Default)]
+enum ForceModel {
+    #[default]
+    ParticleLife,
+    Boids,
+    Gravity,
+}
+
+impl ForceModel {
+    fn next(self) -> Self {
+        match self {
+            ForceModel::ParticleLife => ForceModel::Boids,
+            ForceModel::Boids => ForceModel::Gravity,
+            ForceModel::Gravity => ForceModel::ParticleLife,
+        }
+    }
+}
+
+#[derive(Resource)]
+struct BoidsFactors {
+    separation_radius: f32,
+    separation_weight: f32,
+    alignment_weight: f32,
+    cohesion_weight: f32,
+    max_acceleration: f32,
+}
+
+impl Default for BoidsFactors {
+    fn default() -> Self {
+        Self {
+            separation_radius: 5.0,
+            separation_weight: 10.0,
+            alignment_weight: 1.0,
+            cohesion_weight: 1.0,
+            max_acceleration: 100.0,
+        }
+    }
+}
+
+#[derive(Resource, Clone, Copy)]
+struct GravityFactors {
+    g: f32,
+    theta: f32,
+    softening: f32,
+}
+
+impl Default for GravityFactors {
+    fn default() -> Self {
+        Self {
+            g: 50.0,
+            theta: 0.5,
+            softening: 2.0,
+        }
+    }
+}
+
+struct QuadtreeNode {
+    center: Vec2,
+    half_size: f32,
+    mass: f32,
+    center_of_mass: Vec2,
+    children: Option<Box<[Option<QuadtreeNode>; 4]>>,
+}
+
+fn build_quadtree(bodies: &[(Vec2, f32)], center: Vec2, half_size: f32) -> Option<QuadtreeNode> {
+    if bodies.is_empty() {
+        return None;
+    }
+
+    let total_mass: f32 = bodies.iter().map(|(_, mass)| mass).sum();
+    let center_of_mass: Vec2 = bodies
+        .iter()
+        .map(|(position, mass)| *position * *mass)
+        .sum::<Vec2>()
+        / total_mass;
+
+    if bodies.len() == 1 || half_size < 1e-3 {
+        return Some(QuadtreeNode {
+            center,
+            half_size,
+            mass: total_mass,
+            center_of_mass,
+            children: None,
+        });
+    }
+
+    let mut quadrants: [Vec<(Vec2, f32)>; 4] = Default::default();
+    for &(position, mass) in bodies {
+        quadrants[quadrant_index(position, center)].push((position, mass));
+    }
+
+    let half = half_size / 2.0;
+    let offsets = [
+        Vec2::new(-half, -half),
+        Vec2::new(half, -half),
+        Vec2::new(-half, half),
+        Vec2::new(half, half),
+    ];
+
+    let mut children: [Option<QuadtreeNode>; 4] = Default::default();
+    for (index, (quadrant, offset)) in quadrants.into_iter().zip(offsets).enumerate() {
+        children[index] = build_quadtree(&quadrant, center + offset, half);
+    }
+
+    Some(QuadtreeNode {
+        center,
+        half_size,
+        mass: total_mass,
+        center_of_mass,
+        children: Some(Box::new(children)),
+    })
+}
+
+fn quadrant_index(position: Vec2, center: Vec2) -> usize {
+    match (position.x < center.x, position.y < center.y) {
+        (true, true) => 0,
+        (false, true) => 1,
+        (true, false) => 2,
+        (false, false) => 3,
+    }
+}
+
+fn quadtree_force(node: &QuadtreeNode, position: Vec2, gravity_factors: &GravityFactors) -> Vec2 {
+    let offset = node.center_of_mass - position;
+    let distance_squared = offset.length_squared() + gravity_factors.softening.powi(2);
+    let distance = distance_squared.sqrt();
+
+    match &node.children {
+        None => gravity_factors.g * node.mass / distance_squared * (offset / distance),
+        Some(children) => {
+            if node.half_size * 2.0 / distance < gravity_factors.theta {
+                gravity_factors.g * node.mass / distance_squared * (offset / distance)
+            } else {
+                children
+                    .iter()
+                    .filter_map(|child| child.as_ref())
+                    .map(|child| quadtree_force(child, position, gravity_factors))
+                    .sum()
+            }
+        }
+    }
+}
+
+#[derive(Resource)]
+struct CursorInteraction {
+    radius: f32,
+    strength: f32,
+    spawn_count: usize,
+    spawn_speed: f32,
+}
+
+impl Default for CursorInteraction {
+    fn default() -> Self {
+        Self {
+            radius: 60.0,
+            strength: 400.0,
+            spawn_count: 8,
+            spawn_speed: 30.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum CursorForceMode {
+    Attract,
+    Repel,
+}
+
+#[derive(Resource, Default)]
+struct CursorState {
+    world_position: Option<Vec2>,
+    mode: Option<CursorForceMode>,
+}
+
+#[derive(Resource)]
+struct ParticleColors(Vec<Color>);
+
+#[derive(Resource)]
+struct NoiseField {
+    noise_x: OpenSimplexNoise,
+    noise_y: OpenSimplexNoise,
+    frequency: f32,
+    strength: f32,
+    time_scale: f32,
+    elapsed: f32,
+}
+
+impl NoiseField {
+    fn new(seed: i64) -> Self {
+        Self {
+            noise_x: OpenSimplexNoise::new(Some(seed)),
+            noise_y: OpenSimplexNoise::new(Some(seed.wrapping_add(1))),
+            frequency: 0.01,
+            strength: 15.0,
+            time_scale: 0.1,
+            elapsed: 0.0,
+        }
+    }
+}
+
+fn noise_acceleration(position: Position, noise_field: &NoiseField) -> Vec2 {
+    let x = (position.0.x * noise_field.frequency) as f64;
+    let y = (position.0.y * noise_field.frequency) as f64;
+    let t = (noise_field.elapsed * noise_field.time_scale) as f64;
+    let dx = noise_field.noise_x.eval_3d(x, y, t) as f32;
+    let dy = noise_field.noise_y.eval_3d(x, y, t) as f32;
+    Vec2::new(dx, dy) * noise_field.strength
+}
+
+#[derive(Resource)]
+struct CollisionFactors {
+    fragment_speed_threshold: f32,
+    fragment_count: usize,
+    restitution: f32,
+}
+
+impl Default for CollisionFactors {
+    fn default() -> Self {
+        Self {
+            fragment_speed_threshold: 80.0,
+            fragment_count: 3,
+            restitution: 0.6,
+        }
+    }
+}
+
+fn cycle_force_model(mut force_model: ResMut<ForceModel>, keyboard: Res<ButtonInput<KeyCode>>) {
+    if keyboard.just_pressed(KeyCode::Tab) {
+        *force_model = force_model.next();
+    }
+}
+
+fn handle_mouse_interaction(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    colors: Res<ParticleColors>,
+    cursor_interaction: Res<CursorInteraction>,
+    mut cursor_state: ResMut<CursorState>,
+    windows: Query<&Window>,
+    cameras: Query<(&Camera, &GlobalTransform)>,
+    mouse_buttons: Res<ButtonInput<MouseButton>>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+) {
+    cursor_state.world_position = None;
+    cursor_state.mode = None;
+
+    let Ok(window) = windows.get_single() else {
+        return;
+    };
+    let Ok((camera, camera_transform)) = cameras.get_single() else {
+        return;
+    };
+    let Some(cursor_position) = window.cursor_position() else {
+        return;
+    };
+    let Some(world_position) = camera.viewport_to_world_2d(camera_transform, cursor_position)
+    else {
+        return;
+    };
+
+    if mouse_buttons.pressed(MouseButton::Left) {
+        let mode = if keyboard.pressed(KeyCode::ShiftLeft) || keyboard.pressed(KeyCode::ShiftRight)
+        {
+            CursorForceMode::Repel
+        } else {
+            CursorForceMode::Attract
+        };
+        cursor_state.world_position = Some(world_position);
+        cursor_state.mode = Some(mode);
+    }
+
+    if mouse_buttons.just_pressed(MouseButton::Right) {
+        let mut rng = thread_rng();
+        for _ in 0..cursor_interaction.spawn_count {
+            let ty = Type(rng.gen_range(0..colors.0.len()));
+            let angle = rng.gen::<f32>() * TAU;
+            let speed = rng.gen::<f32>() * cursor_interaction.spawn_speed;
+            let velocity = Vec2::new(angle.cos(), angle.sin()) * speed;
+            spawn_particle(
+                &mut commands,
+                &mut meshes,
+                &mut materials,
+                &colors.0,
+                ParticleSpawn {
+                    location: Position(world_position),
+                    ty,
+                    velocity: Velocity(velocity),
+                    radius: PARTICLE_SIZE,
+                },
+            );
+        }
+    }
+}
+
 fn setup(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
@@ -130,13 +462,27 @@ fn setup(
             &mut meshes,
             &mut materials,
             &colors,
-            Position(Vec2::new(
-                (x - 0.5) * WINDOW_WIDTH,
-                (y - 0.5) * WINDOW_HEIGHT,
-            )),
-            Type(ty),
+            ParticleSpawn {
+                location: Position(Vec2::new(
+                    (x - 0.5) * WINDOW_WIDTH,
+                    (y - 0.5) * WINDOW_HEIGHT,
+                )),
+                ty: Type(ty),
+                velocity: Velocity::default(),
+                radius: PARTICLE_SIZE,
+            },
         );
     }
+
+    commands.insert_resource(ParticleColors(colors));
+    commands.insert_resource(NoiseField::new(rng.gen()));
+}
+
+struct ParticleSpawn {
+    location: Position,
+    ty: Type,
+    velocity: Velocity,
+    radius: f32,
 }
 
 fn spawn_particle(
@@ -144,24 +490,31 @@ fn spawn_particle(
     meshes: &mut Assets<Mesh>,
     materials: &mut Assets<ColorMaterial>,
     colors: &[Color],
-    location: Position,
-    ty: Type,
-) {
-    let coordinates = location.0.extend(0.0);
-    commands.spawn(Particle {
-        point: Point,
-        velocity: Velocity::default(),
-        position: location,
+    spawn: ParticleSpawn,
+) -> Entity {
+    let ParticleSpawn {
+        location,
         ty,
-        mesh: MaterialMesh2dBundle {
-            mesh: Mesh2dHandle(meshes.add(Circle {
-                radius: PARTICLE_SIZE,
-            })),
-            material: materials.add(colors[ty.0]),
-            transform: Transform::from_xyz(coordinates.x, coordinates.y, coordinates.z),
-            ..Default::default()
-        },
-    });
+        velocity,
+        radius,
+    } = spawn;
+    let coordinates = location.0.extend(0.0);
+    commands
+        .spawn(Particle {
+            point: Point,
+            velocity,
+            position: location,
+            ty,
+            mass: Mass(mass_for_radius(radius)),
+            radius: Radius(radius),
+            mesh: MaterialMesh2dBundle {
+                mesh: Mesh2dHandle(meshes.add(Circle { radius })),
+                material: materials.add(colors[ty.0]),
+                transform: Transform::from_xyz(coordinates.x, coordinates.y, coordinates.z),
+                ..Default::default()
+            },
+        })
+        .id()
 }
 
 fn position_to_translation(mut positions: Query<(&Position, &mut Transform)>) {
@@ -170,67 +523,201 @@ fn position_to_translation(mut positions: Query<(&Position, &mut Transform)>) {
     }
 }
 
+#[derive(Clone, Copy)]
+struct ParticleSnapshot {
+    entity: Entity,
+    position: Position,
+    ty: Type,
+    velocity: Velocity,
+}
+
 fn get_particles(
-    particles: Query<(&Transform, Entity), With<Point>>,
+    particles: Query<(Entity, &Position, &Type, &Velocity), With<Point>>,
     tree: Res<PointKDTree>,
-) -> Vec<Vec<Entity>> {
-    let mut list_of_nearest_entities = Vec::new();
-    for (transform, this_entity) in particles.iter() {
-        // We add it as the first entity.
-        // This would make using `get_many_entities_dynamic` easier.
-        let mut nearest_entities = vec![this_entity];
-        for (_, other_entity) in
-            tree.within_distance(transform.translation.truncate(), MAXIMUM_RADIUS_OF_EFFECT)
-        {
-            let other_entity = other_entity.unwrap();
-            if this_entity == other_entity {
-                continue;
-            }
-            nearest_entities.push(other_entity);
-        }
-        list_of_nearest_entities.push(nearest_entities);
+    force_model: Res<ForceModel>,
+) -> (Vec<ParticleSnapshot>, Vec<Vec<usize>>) {
+    let snapshot: Vec<ParticleSnapshot> = particles
+        .iter()
+        .map(|(entity, position, ty, velocity)| ParticleSnapshot {
+            entity,
+            position: *position,
+            ty: *ty,
+            velocity: *velocity,
+        })
+        .collect();
+
+    if *force_model == ForceModel::Gravity {
+        return (snapshot, Vec::new());
     }
-    list_of_nearest_entities
+
+    let dense_id_by_entity: HashMap<Entity, usize> = snapshot
+        .iter()
+        .enumerate()
+        .map(|(dense_id, particle)| (particle.entity, dense_id))
+        .collect();
+
+    let neighbor_lists: Vec<Vec<usize>> = snapshot
+        .iter()
+        .map(|particle| {
+            tree.within_distance(particle.position.0, MAXIMUM_RADIUS_OF_EFFECT)
+                .into_iter()
+                .filter_map(|(_, other_entity)| {
+                    let other_entity = other_entity.unwrap();
+                    if other_entity == particle.entity {
+                        return None;
+                    }
+                    dense_id_by_entity.get(&other_entity).copied()
+                })
+                .collect()
+        })
+        .collect();
+
+    (snapshot, neighbor_lists)
 }
 
 fn compute_acceleration(
-    In(list_of_nearest_entites): In<Vec<Vec<Entity>>>,
-    // We want to use `get_many_entities_dynamic`
-    world: &mut World,
+    In((snapshot, neighbor_lists)): In<(Vec<ParticleSnapshot>, Vec<Vec<usize>>)>,
+    attraction_factors: Res<AttractionFactors>,
+    boids_factors: Res<BoidsFactors>,
+    gravity_factors: Res<GravityFactors>,
+    force_model: Res<ForceModel>,
+    mut noise_field: ResMut<NoiseField>,
+    time: Res<Time<Fixed>>,
 ) -> Vec<(Entity, Vec2)> {
-    let mut accelerations = Vec::new();
-    let attraction_factors = world.get_resource::<AttractionFactors>().unwrap();
-    for entities in list_of_nearest_entites {
-        let entities = world.get_many_entities_dynamic(&entities).unwrap();
-        let this_entity = entities[0];
-        let this_type: Type = *this_entity.get().unwrap();
-        let this_position: Position = *this_entity.get().unwrap();
-        let mut acceleration = Vec2::default();
-        for other_entity in &entities[1..] {
-            let other_type: Type = *other_entity.get().unwrap();
-            let other_position: Position = *other_entity.get().unwrap();
-            let vector = other_position - this_position;
-            let distance = vector.0.length();
-            let force = force(
-                attraction_factors.get_factor(this_type, other_type),
-                distance / MAXIMUM_RADIUS_OF_EFFECT,
-            );
-            acceleration += (vector.0 / distance) * force;
+    noise_field.elapsed += time.delta_seconds();
+
+    if *force_model == ForceModel::Gravity {
+        return compute_gravity_acceleration(&snapshot, &gravity_factors, &noise_field);
+    }
+
+    snapshot
+        .par_iter()
+        .zip(neighbor_lists.par_iter())
+        .map(|(particle, neighbors)| {
+            let neighbors: Vec<&ParticleSnapshot> = neighbors
+                .iter()
+                .map(|&dense_id| &snapshot[dense_id])
+                .collect();
+            let acceleration = match *force_model {
+                ForceModel::ParticleLife => {
+                    particle_life_acceleration(particle, &neighbors, &attraction_factors)
+                }
+                ForceModel::Boids => boids_acceleration(
+                    particle.position,
+                    particle.velocity,
+                    &neighbors,
+                    &boids_factors,
+                ),
+                ForceModel::Gravity => unreachable!("handled above before the neighbor loop"),
+            };
+            let acceleration = acceleration + noise_acceleration(particle.position, &noise_field);
+            (particle.entity, acceleration)
+        })
+        .collect()
+}
+
+fn particle_life_acceleration(
+    this: &ParticleSnapshot,
+    neighbors: &[&ParticleSnapshot],
+    attraction_factors: &AttractionFactors,
+) -> Vec2 {
+    let mut acceleration = Vec2::default();
+    for other in neighbors {
+        let vector = other.position - this.position;
+        let distance = vector.0.length();
+        let force = force(
+            attraction_factors.get_factor(this.ty, other.ty),
+            distance / MAXIMUM_RADIUS_OF_EFFECT,
+        );
+        acceleration += (vector.0 / distance) * force;
+    }
+    acceleration * MAXIMUM_RADIUS_OF_EFFECT
+}
+
+fn compute_gravity_acceleration(
+    snapshot: &[ParticleSnapshot],
+    gravity_factors: &GravityFactors,
+    noise_field: &NoiseField,
+) -> Vec<(Entity, Vec2)> {
+    let bodies: Vec<(Vec2, f32)> = snapshot
+        .iter()
+        .map(|particle| (particle.position.0, 1.0))
+        .collect();
+    let half_size = WINDOW_WIDTH.max(WINDOW_HEIGHT) / 2.0;
+    let quadtree = build_quadtree(&bodies, Vec2::ZERO, half_size);
+
+    snapshot
+        .par_iter()
+        .map(|particle| {
+            let acceleration = quadtree
+                .as_ref()
+                .map(|node| quadtree_force(node, particle.position.0, gravity_factors))
+                .unwrap_or_default()
+                + noise_acceleration(particle.position, noise_field);
+            (particle.entity, acceleration)
+        })
+        .collect()
+}
+
+fn boids_acceleration(
+    this_position: Position,
+    this_velocity: Velocity,
+    neighbors: &[&ParticleSnapshot],
+    boids_factors: &BoidsFactors,
+) -> Vec2 {
+    let mut separation = Vec2::ZERO;
+    let mut velocity_sum = Vec2::ZERO;
+    let mut position_sum = Vec2::ZERO;
+    let neighbor_count = neighbors.len();
+    for other in neighbors {
+        let vector = other.position - this_position;
+        let distance = vector.0.length();
+        if distance > f32::EPSILON && distance < boids_factors.separation_radius {
+            separation -= (vector.0 / distance) / distance;
         }
-        acceleration *= MAXIMUM_RADIUS_OF_EFFECT;
-        accelerations.push((this_entity.id(), acceleration));
+        velocity_sum += other.velocity.0;
+        position_sum += other.position.0;
     }
-    accelerations
+    if neighbor_count == 0 {
+        return Vec2::ZERO;
+    }
+    let alignment = velocity_sum / neighbor_count as f32 - this_velocity.0;
+    let cohesion = position_sum / neighbor_count as f32 - this_position.0;
+
+    let mut acceleration = boids_factors.separation_weight * separation
+        + boids_factors.alignment_weight * alignment
+        + boids_factors.cohesion_weight * cohesion;
+    if acceleration.length() > boids_factors.max_acceleration {
+        acceleration = acceleration.normalize() * boids_factors.max_acceleration;
+    }
+    acceleration
 }
 
 fn apply_acceleration(
     In(accelerations): In<Vec<(Entity, Vec2)>>,
     mut pos_vels: Query<(&mut Position, &mut Velocity)>,
     time: Res<Time<Fixed>>,
+    cursor_state: Res<CursorState>,
+    cursor_interaction: Res<CursorInteraction>,
 ) {
     let delta_time = time.delta_seconds();
-    for (this_entity, acceleration) in accelerations {
+    for (this_entity, mut acceleration) in accelerations {
         let (mut position, mut velocity) = pos_vels.get_mut(this_entity).unwrap();
+
+        if let (Some(cursor_position), Some(mode)) =
+            (cursor_state.world_position, cursor_state.mode)
+        {
+            let vector = cursor_position - position.0;
+            let distance = vector.length();
+            if distance > f32::EPSILON && distance < cursor_interaction.radius {
+                let sign = match mode {
+                    CursorForceMode::Attract => 1.0,
+                    CursorForceMode::Repel => -1.0,
+                };
+                acceleration += sign * cursor_interaction.strength * (vector / distance);
+            }
+        }
+
         let old_velocity = velocity.0;
         let friction = 0.5f32.powf(delta_time / FRICTION_HALF_TIME);
         velocity.0 = friction * old_velocity + acceleration * delta_time;
@@ -248,6 +735,115 @@ fn wrap_particles(mut particles: Query<&mut Position, With<Point>>) {
     }
 }
 
+fn handle_collisions(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    colors: Res<ParticleColors>,
+    collision_factors: Res<CollisionFactors>,
+    particles: Query<(Entity, &Position, &Velocity, &Mass, &Radius, &Type), With<Point>>,
+    tree: Res<PointKDTree>,
+) {
+    let mut rng = thread_rng();
+    let mut resolved: HashSet<Entity> = HashSet::new();
+
+    let max_radius = particles
+        .iter()
+        .map(|(.., radius, _)| radius.0)
+        .fold(0.0f32, f32::max);
+    let search_radius = MAXIMUM_RADIUS_OF_EFFECT + 2.0 * max_radius;
+
+    for (this_entity, this_position, this_velocity, this_mass, this_radius, this_type) in
+        particles.iter()
+    {
+        if resolved.contains(&this_entity) {
+            continue;
+        }
+
+        for (_, other_entity) in tree.within_distance(this_position.0, search_radius) {
+            let other_entity = other_entity.unwrap();
+            if other_entity == this_entity || resolved.contains(&other_entity) {
+                continue;
+            }
+            let Ok((_, other_position, other_velocity, other_mass, other_radius, other_type)) =
+                particles.get(other_entity)
+            else {
+                continue;
+            };
+
+            let distance = (other_position.0 - this_position.0).length();
+            if distance >= this_radius.0 + other_radius.0 {
+                continue;
+            }
+
+            resolved.insert(this_entity);
+            resolved.insert(other_entity);
+            commands.entity(this_entity).despawn();
+            commands.entity(other_entity).despawn();
+
+            let total_mass = this_mass.0 + other_mass.0;
+            let center =
+                (this_position.0 * this_mass.0 + other_position.0 * other_mass.0) / total_mass;
+            let average_velocity =
+                (this_velocity.0 * this_mass.0 + other_velocity.0 * other_mass.0) / total_mass;
+            let dominant_type = if this_mass.0 >= other_mass.0 {
+                *this_type
+            } else {
+                *other_type
+            };
+            let impact_speed = (other_velocity.0 - this_velocity.0).length();
+
+            if impact_speed > collision_factors.fragment_speed_threshold {
+                let fragment_mass = total_mass / collision_factors.fragment_count as f32;
+                let fragment_radius = fragment_mass.sqrt();
+                let base_angle = rng.gen::<f32>() * TAU;
+                let kicks: Vec<Vec2> = (0..collision_factors.fragment_count)
+                    .map(|index| {
+                        let angle = base_angle
+                            + index as f32 / collision_factors.fragment_count as f32 * TAU;
+                        Vec2::new(angle.cos(), angle.sin())
+                            * impact_speed
+                            * collision_factors.restitution
+                    })
+                    .collect();
+                let kick_mean = kicks.iter().copied().sum::<Vec2>() / kicks.len() as f32;
+
+                for kick in kicks {
+                    let jitter =
+                        Vec2::new(rng.gen::<f32>() - 0.5, rng.gen::<f32>() - 0.5) * fragment_radius;
+                    spawn_particle(
+                        &mut commands,
+                        &mut meshes,
+                        &mut materials,
+                        &colors.0,
+                        ParticleSpawn {
+                            location: Position(center + jitter),
+                            ty: dominant_type,
+                            velocity: Velocity(average_velocity + kick - kick_mean),
+                            radius: fragment_radius,
+                        },
+                    );
+                }
+            } else {
+                spawn_particle(
+                    &mut commands,
+                    &mut meshes,
+                    &mut materials,
+                    &colors.0,
+                    ParticleSpawn {
+                        location: Position(center),
+                        ty: dominant_type,
+                        velocity: Velocity(average_velocity),
+                        radius: total_mass.sqrt(),
+                    },
+                );
+            }
+
+            break;
+        }
+    }
+}
+
 fn force(attraction_factor: f32, distance: f32) -> f32 {
     if distance < BETA_REPULSION_DISTANCE {
         distance / BETA_REPULSION_DISTANCE - 1.0